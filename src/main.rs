@@ -1,62 +1,359 @@
+use futures::future::select_all;
 use rlua::prelude::*;
-use rlua::{Function, Nil, RegistryKey, Thread, ThreadStatus, UserData, UserDataMethods, Value};
+use rlua::{
+    Context, Function, Nil, RegistryKey, Table, Thread, ThreadStatus, UserData, UserDataMethods,
+    Value, Variadic,
+};
+use std::collections::HashMap;
 use std::env;
 use std::fs::File;
 use std::future::Future;
 use std::io::Read;
 use std::path::Path;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::{mpsc, Mutex};
 use tokio::task;
 use tokio::time::delay_for;
 
+/// ホスト側の非同期処理が返す結果を表す、boxed future のエイリアス。
+/// `run_coroutine` が返す future が `Send` である必要があるので、これも
+/// `Send` でなければならない。
+type AsyncResult = Pin<Box<dyn Future<Output = Result<ResumeData, rlua::Error>> + Send>>;
+/// `App::register_async` で登録されるハンドラ。引数は Lua レジストリに
+/// 積まれたテーブルのキーとして渡される。
+type AsyncHandler = Arc<dyn Fn(Arc<RegistryKey>) -> AsyncResult + Send + Sync>;
+
+#[derive(Clone, Debug)]
+enum JobResult {
+    // コルーチンが複数の値を return した場合、そのすべてを保持する。
+    Value(Vec<Arc<RegistryKey>>),
+    Error(String),
+}
+
+/// HTTP レスポンスを表す Lua 向けの userdata。本文は `:text()` か `:json()`
+/// で取り出すまでデコードしない。
+struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+impl UserData for Response {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("status", |_, res, ()| Ok(res.status));
+        methods.add_method("headers", |lua, res, ()| {
+            let table = lua.create_table()?;
+            for (k, v) in &res.headers {
+                table.set(k.as_str(), v.as_str())?;
+            }
+            Ok(table)
+        });
+        methods.add_method("text", |_, res, ()| Ok(res.body.clone()));
+        methods.add_method("json", |lua, res, ()| {
+            let value: serde_json::Value = serde_json::from_str(&res.body)
+                .map_err(|e| rlua::Error::RuntimeError(e.to_string()))?;
+            json_to_lua(lua, &value)
+        });
+    }
+}
+
+/// `serde_json::Value` を対応する Lua の値に変換する。
+fn json_to_lua<'lua>(lua: Context<'lua>, value: &serde_json::Value) -> LuaResult<Value<'lua>> {
+    Ok(match value {
+        serde_json::Value::Null => Nil,
+        serde_json::Value::Bool(b) => Value::Boolean(*b),
+        serde_json::Value::Number(n) => match n.as_i64() {
+            Some(i) => Value::Integer(i),
+            None => Value::Number(n.as_f64().unwrap_or(0.0)),
+        },
+        serde_json::Value::String(s) => Value::String(lua.create_string(s)?),
+        serde_json::Value::Array(arr) => {
+            let table = lua.create_table()?;
+            for (i, v) in arr.iter().enumerate() {
+                table.set((i + 1) as i64, json_to_lua(lua, v)?)?;
+            }
+            Value::Table(table)
+        }
+        serde_json::Value::Object(obj) => {
+            let table = lua.create_table()?;
+            for (k, v) in obj.iter() {
+                table.set(k.as_str(), json_to_lua(lua, v)?)?;
+            }
+            Value::Table(table)
+        }
+    })
+}
+
+// `request`/`wait_any`/タイムアウト付きの `job:wait` は、固有の `IO` variant を
+// 増やすのではなく、すべて `Async` 経由で `App::register_async`/`register_handler`
+// に登録されたハンドラに委譲する。新しい非同期処理を追加するときも、この
+// enum を編集する必要はない。
 #[derive(Clone)]
 enum IO {
     Nop,
     Sleep(u64),
     Fork(Arc<RegistryKey>),
-    Get(String),
     Job {
-        receiver: Arc<Mutex<mpsc::Receiver<Arc<RegistryKey>>>>,
+        receiver: Arc<Mutex<mpsc::Receiver<JobResult>>>,
+    },
+    Async {
+        name: String,
+        args: Arc<RegistryKey>,
     },
 }
 impl UserData for IO {}
 
-struct Job(Arc<Mutex<mpsc::Receiver<Arc<RegistryKey>>>>);
+// tokio 0.2 の JoinHandle には abort が無いので、協調的なキャンセルを自前で
+// 実装している。ただのフラグだと次の resume の境界まで気付いてもらえず、
+// `sleep(3600)` のような長い IO で yield 中のコルーチンは cancel() してから
+// 最大でその IO が終わるまで止まらない。`Notify` を組み合わせることで、
+// 今まさに await している IO を cancel() 側から即座に起こせるようにする。
+struct CancelToken {
+    cancelled: AtomicBool,
+    notify: tokio::sync::Notify,
+}
+impl CancelToken {
+    fn new() -> Arc<Self> {
+        Arc::new(Self {
+            cancelled: AtomicBool::new(false),
+            notify: tokio::sync::Notify::new(),
+        })
+    }
+    fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        self.notify.notify();
+    }
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+    // 既にキャンセル済みならすぐ返る。そうでなければ `cancel()` が呼ばれる
+    // まで待つ。`run_coroutine` のループは、キャンセルされた状態で再度
+    // これを待つことはない（その前に抜ける）ので、`notify()` が一度しか
+    // 呼ばれなくても問題にならない。
+    async fn cancelled(&self) {
+        if self.is_cancelled() {
+            return;
+        }
+        self.notify.notified().await;
+    }
+}
+
+struct Job {
+    receiver: Arc<Mutex<mpsc::Receiver<JobResult>>>,
+    // 子コルーチンに対する協調的なキャンセルトークン。
+    cancel: Arc<CancelToken>,
+}
 impl UserData for Job {
     fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
-        methods.add_method("wait", |_, job, ()| {
-            Ok(IO::Job {
-                receiver: job.0.clone(),
-            })
+        methods.add_method(
+            "wait",
+            |lua, job, timeout_secs: Option<u64>| match timeout_secs {
+                Some(timeout_secs) => {
+                    // タイムアウト付きの待ちは "job_wait_timeout" ハンドラに委譲する。
+                    // 引数として、同じチャンネルを指す新しい `Job` userdata と
+                    // タイムアウト秒数を積んだテーブルを渡す。
+                    let job_ud = lua.create_userdata(Job {
+                        receiver: job.receiver.clone(),
+                        cancel: job.cancel.clone(),
+                    })?;
+                    let table = lua.create_table()?;
+                    table.set(1, job_ud)?;
+                    table.set(2, timeout_secs)?;
+                    let key = lua.create_registry_value(table)?;
+                    Ok(IO::Async {
+                        name: "job_wait_timeout".to_string(),
+                        args: Arc::new(key),
+                    })
+                }
+                None => Ok(IO::Job {
+                    receiver: job.receiver.clone(),
+                }),
+            },
+        );
+        methods.add_method("cancel", |_, job, ()| {
+            job.cancel.cancel();
+            Ok(())
         });
     }
 }
 
 enum CoroutineStatus {
     Running { yielded: IO },
-    Finished { retvalue: RegistryKey },
+    // Lua の関数は複数の値を返せるので、終了時の値もすべて保持する。
+    Finished { retvalues: Vec<RegistryKey> },
+    // コルーチン本体は `wrap_for_traceback` で包まれているので、ここに来る
+    // `error` のメッセージには `debug.traceback` によるトレースバックが
+    // 含まれている。
+    Errored { error: rlua::Error },
 }
 
 enum ResumeData {
     Nil,
-    String(String),
-    Key(Arc<RegistryKey>),
+    // `coroutine.yield(...)` に複数の値を返すための、resume 引数のリスト。
+    Values(Vec<Arc<RegistryKey>>),
     Job(Job),
+    Error(String),
+    /// `wait_any()` で待っていた複数のジョブのうち、何番目のジョブが
+    /// 完了したのかとその結果。インデックスは 0 始まり。
+    Selected {
+        index: usize,
+        result: JobResult,
+    },
 }
 
+// コルーチンは `task::spawn` で好きな OS スレッドに乗ることがあるので、
+// `Lua` はスレッドをまたいで共有できる `Arc<Mutex<..>>` で持つ。ロックは
+// いずれの箇所でも `.await` をまたいで保持しないので、複数のコルーチンが
+// 互いの IO 待ちをブロックすることはない。
 #[derive(Clone)]
-struct App(Arc<Mutex<Lua>>);
+struct App {
+    lua: Arc<Mutex<Lua>>,
+    handlers: Arc<std::sync::Mutex<HashMap<String, AsyncHandler>>>,
+    // コネクションを使い回せるよう、`request{}` の呼び出し全体で共有する。
+    client: reqwest::Client,
+}
 
 impl App {
     const MAIN: &'static str = "MAIN";
+    const TRACEBACK_WRAP: &'static str = "TRACEBACK_WRAP";
 
     fn new() -> Self {
         let lua = Lua::new();
         Self::init(&lua);
-        Self(Arc::new(Mutex::new(lua)))
+        let app = Self {
+            lua: Arc::new(Mutex::new(lua)),
+            handlers: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            client: reqwest::Client::new(),
+        };
+        app.register_builtins();
+        app
+    }
+    /// `register_async`/`register_handler` を使って組み込みの非同期処理を登録する。
+    fn register_builtins(&self) {
+        {
+            let app = self.clone();
+            self.register_async("request", move |args_key| {
+                let app = app.clone();
+                async move { app.do_request(args_key).await }
+            });
+        }
+        {
+            let app = self.clone();
+            self.register_async("wait_any", move |args_key| {
+                let app = app.clone();
+                async move { app.do_wait_any(args_key).await }
+            });
+        }
+        {
+            let app = self.clone();
+            // `job:wait(timeout)` からのみ使われるので、Lua のグローバル関数は
+            // 生やさず、ハンドラとしてのみ登録する。
+            self.register_handler("job_wait_timeout", move |args_key| {
+                let app = app.clone();
+                async move { app.do_job_wait_timeout(args_key).await }
+            });
+        }
+    }
+    /// `request{...}` ハンドラ本体。レジストリに積まれた引数テーブルの 1 番目に
+    /// オプションテーブルが入っている。
+    async fn do_request(&self, args_key: Arc<RegistryKey>) -> Result<ResumeData, rlua::Error> {
+        let (method, url, headers, body) =
+            self.lua.lock().await.context(|lua| -> LuaResult<_> {
+                let args: Table = lua.registry_value(&args_key)?;
+                let opts: Table = args.get(1)?;
+                let method = opts
+                    .get::<_, Option<String>>("method")?
+                    .unwrap_or_else(|| "GET".to_string());
+                let url: String = opts.get("url")?;
+                let body = opts.get::<_, Option<String>>("body")?;
+                let headers = match opts.get::<_, Option<Table>>("headers")? {
+                    Some(t) => t.pairs::<String, String>().collect::<LuaResult<Vec<_>>>()?,
+                    None => Vec::new(),
+                };
+                Ok((method, url, headers, body))
+            })?;
+
+        let method = reqwest::Method::from_bytes(method.as_bytes()).unwrap_or(reqwest::Method::GET);
+        let mut builder = self.client.request(method, &url);
+        for (k, v) in &headers {
+            builder = builder.header(k.as_str(), v.as_str());
+        }
+        if let Some(body) = &body {
+            builder = builder.body(body.clone());
+        }
+
+        let res = match builder.send().await {
+            Ok(res) => res,
+            Err(error) => return Ok(ResumeData::Error(error.to_string())),
+        };
+        let status = res.status().as_u16();
+        let headers = res
+            .headers()
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_str().unwrap_or("").to_string()))
+            .collect();
+        let body = match res.text().await {
+            Ok(body) => body,
+            Err(error) => return Ok(ResumeData::Error(error.to_string())),
+        };
+        let key = self.lua.lock().await.context(|lua| {
+            lua.create_registry_value(Response {
+                status,
+                headers,
+                body,
+            })
+            .unwrap()
+        });
+        Ok(ResumeData::Values(vec![Arc::new(key)]))
+    }
+    /// `wait_any(job1, job2, ...)` ハンドラ本体。
+    async fn do_wait_any(&self, args_key: Arc<RegistryKey>) -> Result<ResumeData, rlua::Error> {
+        let receivers = self.lua.lock().await.context(|lua| -> LuaResult<_> {
+            let args: Table = lua.registry_value(&args_key)?;
+            args.sequence_values::<rlua::AnyUserData>()
+                .map(|job| Ok(job?.borrow::<Job>()?.receiver.clone()))
+                .collect::<LuaResult<Vec<_>>>()
+        })?;
+        let recvs: Vec<_> = receivers
+            .iter()
+            .map(|rx| {
+                let rx = rx.clone();
+                Box::pin(async move { rx.lock().await.recv().await })
+            })
+            .collect();
+        let (result, index, _rest) = select_all(recvs).await;
+        Ok(match result {
+            Some(result) => ResumeData::Selected { index, result },
+            // すでに wait され済みの job の場合
+            None => ResumeData::Nil,
+        })
+    }
+    /// `job:wait(timeout_secs)` ハンドラ本体。引数テーブルの 1 番目に対象の
+    /// `Job` userdata、2 番目にタイムアウト秒数が入っている。
+    async fn do_job_wait_timeout(
+        &self,
+        args_key: Arc<RegistryKey>,
+    ) -> Result<ResumeData, rlua::Error> {
+        let (rx, timeout_secs) = self.lua.lock().await.context(|lua| -> LuaResult<_> {
+            let args: Table = lua.registry_value(&args_key)?;
+            let job_ud: rlua::AnyUserData = args.get(1)?;
+            let timeout_secs: u64 = args.get(2)?;
+            let rx = job_ud.borrow::<Job>()?.receiver.clone();
+            Ok((rx, timeout_secs))
+        })?;
+        let recv_fut = async { rx.lock().await.recv().await };
+        tokio::pin!(recv_fut);
+        Ok(tokio::select! {
+            r = &mut recv_fut => match r {
+                Some(JobResult::Value(keys)) => ResumeData::Values(keys),
+                Some(JobResult::Error(err)) => ResumeData::Error(err),
+                // すでに wait され済みの job の場合
+                None => ResumeData::Nil,
+            },
+            _ = delay_for(Duration::from_secs(timeout_secs)) => ResumeData::Nil,
+        })
     }
     fn init(lua: &Lua) {
         lua.context(|lua| {
@@ -85,10 +382,82 @@ impl App {
                 )
                 .unwrap();
 
+            // `lua_resume` には xpcall のようなメッセージハンドラを渡す口が無く、
+            // コルーチンの中で起きたエラーはそのままではトレースバックを失ってしまう。
+            // そのため、コルーチン本体になる関数は必ずこのラッパーを通し、
+            // `xpcall(f, debug.traceback, ...)` でエラー発生時にその場で
+            // `debug.traceback` を呼んでから `error()` で投げ直す。
+            let wrap: Function = lua
+                .load(
+                    r#"
+                        return function(f)
+                            return function(...)
+                                local results = table.pack(xpcall(f, debug.traceback, ...))
+                                if not results[1] then
+                                    error(results[2], 0)
+                                end
+                                return table.unpack(results, 2, results.n)
+                            end
+                        end
+                    "#,
+                )
+                .set_name("traceback_wrap")
+                .unwrap()
+                .eval()
+                .unwrap();
+            lua.set_named_registry_value(Self::TRACEBACK_WRAP, wrap)
+                .unwrap();
+        });
+    }
+    /// コルーチン本体にする関数を、エラー時にトレースバックを残す形に包む。
+    fn wrap_for_traceback<'lua>(lua: Context<'lua>, func: Function<'lua>) -> Function<'lua> {
+        let wrap: Function = lua.named_registry_value(Self::TRACEBACK_WRAP).unwrap();
+        wrap.call(func).unwrap()
+    }
+    /// `name` をキーに、レジストリ上のテーブルを引数として受け取るだけの
+    /// ハンドラを登録する。Lua のグローバル関数は生やさない。`job:wait(timeout)`
+    /// のように、呼び出し元が独自に `IO::Async { name, args }` を組み立てる
+    /// ケース向け。
+    fn register_handler<F, Fut>(&self, name: &str, f: F)
+    where
+        F: Fn(Arc<RegistryKey>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<ResumeData, rlua::Error>> + Send + 'static,
+    {
+        let handler: AsyncHandler = Arc::new(move |args| Box::pin(f(args)));
+        self.handlers
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), handler);
+    }
+    /// 新しい非同期の組み込み関数を Lua に登録する。`name` が Lua のグローバル
+    /// 関数名になり、呼び出し時の引数はレジストリ上のテーブルにまとめられて
+    /// `f` に渡される。`f` は `run_coroutine` のループから直接 await される。
+    fn register_async<F, Fut>(&self, name: &str, f: F)
+    where
+        F: Fn(Arc<RegistryKey>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<ResumeData, rlua::Error>> + Send + 'static,
+    {
+        self.register_handler(name, f);
+
+        let name = name.to_string();
+        // ここで呼ばれる時点では `self` をまだ他のタスクと共有していないので、
+        // ロックは必ず即座に取れる。
+        self.lua.try_lock().unwrap().context(|lua| {
             lua.globals()
                 .set(
-                    "get",
-                    lua.create_function(|_, url| Ok(IO::Get(url))).unwrap(),
+                    name.clone(),
+                    lua.create_function(move |lua, args: Variadic<Value>| {
+                        let table = lua.create_table()?;
+                        for (i, value) in args.into_iter().enumerate() {
+                            table.set((i + 1) as i64, value)?;
+                        }
+                        let key = lua.create_registry_value(table)?;
+                        Ok(IO::Async {
+                            name: name.clone(),
+                            args: Arc::new(key),
+                        })
+                    })
+                    .unwrap(),
                 )
                 .unwrap();
         });
@@ -98,7 +467,7 @@ impl App {
         let mut script = String::new();
         f.read_to_string(&mut script).unwrap();
 
-        self.0.lock().await.context(|lua| {
+        self.lua.lock().await.context(|lua| {
             let main: Function = lua
                 .load(&script)
                 .set_name(path.to_str().unwrap())
@@ -109,99 +478,214 @@ impl App {
         });
     }
     async fn main(self) {
-        let key = self.0.lock().await.context(|lua| {
+        let key = self.lua.lock().await.context(|lua| {
             let main: Function = lua.named_registry_value(Self::MAIN).unwrap();
+            let main = Self::wrap_for_traceback(lua, main);
             let coro: Thread = lua.create_thread(main).unwrap();
             lua.create_registry_value(coro).unwrap()
         });
-        self.run_coroutine(Arc::new(key), None).await
+        self.run_coroutine(Arc::new(key), None, CancelToken::new())
+            .await
     }
     fn run_coroutine(
         self,
         key: Arc<RegistryKey>,
-        out: Option<mpsc::Sender<Arc<RegistryKey>>>,
+        out: Option<mpsc::Sender<JobResult>>,
+        cancel: Arc<CancelToken>,
     ) -> Pin<Box<dyn Future<Output = ()> + Send>> {
         // XXX: async fn で再起的なことしようとすると、なんかエラーになる。
         //      というのを回避するために色々やってたらこんなんになった。何か無駄なことをしているかもしれない。
         Box::pin(async move {
             let mut data = ResumeData::Nil;
             loop {
+                // resume の境界でキャンセルをチェックし、cancel() されていれば
+                // ここで静かに止まる。
+                if cancel.is_cancelled() {
+                    if let Some(mut out) = out {
+                        out.send(JobResult::Error("cancelled".to_string()))
+                            .await
+                            .unwrap();
+                    }
+                    break;
+                }
                 let status = self.resume_coroutine(&key, data).await;
                 match status {
-                    CoroutineStatus::Finished { retvalue: key } => {
+                    CoroutineStatus::Finished { retvalues } => {
                         if let Some(mut out) = out {
-                            out.send(Arc::new(key)).await.unwrap();
+                            let keys = retvalues.into_iter().map(Arc::new).collect();
+                            out.send(JobResult::Value(keys)).await.unwrap();
+                        }
+                        break;
+                    }
+                    CoroutineStatus::Errored { error } => {
+                        if let Some(mut out) = out {
+                            out.send(JobResult::Error(error.to_string())).await.unwrap();
+                        } else {
+                            // トップレベルのコルーチンがエラーだった場合は、
+                            // プロセス全体を巻き込まずにトレースバックを表示して終了する。
+                            eprintln!("uncaught error in coroutine:\n{}", error);
                         }
                         break;
                     }
                     CoroutineStatus::Running { yielded: io } => {
-                        data = ResumeData::Nil;
-                        match io {
-                            IO::Nop => task::yield_now().await,
-                            IO::Sleep(sec) => delay_for(Duration::from_secs(sec)).await,
-                            IO::Fork(k) => {
-                                let key = self.0.lock().await.context(|lua| {
-                                    let func: Function = lua.registry_value(&k).unwrap();
-                                    let coro: Thread = lua.create_thread(func).unwrap();
-                                    lua.create_registry_value(coro).unwrap()
-                                });
-                                let this = self.clone();
-                                let (tx, rx) = mpsc::channel(1);
-                                task::spawn(this.run_coroutine(Arc::new(key), Some(tx)));
-                                data = ResumeData::Job(Job(Arc::new(Mutex::new(rx))));
-                            }
-                            IO::Get(url) => {
-                                let r = reqwest::get(&url).await.unwrap();
-                                data = ResumeData::String(r.text().await.unwrap());
+                        // 今回 yield された IO を、cancel() と競争させながら待つ。
+                        // これにより、`sleep(3600)` のような長い IO の途中で
+                        // cancel() された場合でも、次の resume 境界（最大でその
+                        // IO が終わるまで）を待たずにその場で止まれる。
+                        let io_fut = async {
+                            match io {
+                                IO::Nop => {
+                                    task::yield_now().await;
+                                    ResumeData::Nil
+                                }
+                                IO::Sleep(sec) => {
+                                    delay_for(Duration::from_secs(sec)).await;
+                                    ResumeData::Nil
+                                }
+                                IO::Fork(k) => {
+                                    let key = self.lua.lock().await.context(|lua| {
+                                        let func: Function = lua.registry_value(&k).unwrap();
+                                        let func = Self::wrap_for_traceback(lua, func);
+                                        let coro: Thread = lua.create_thread(func).unwrap();
+                                        lua.create_registry_value(coro).unwrap()
+                                    });
+                                    let this = self.clone();
+                                    let (tx, rx) = mpsc::channel(1);
+                                    let job_cancel = CancelToken::new();
+                                    task::spawn(this.run_coroutine(
+                                        Arc::new(key),
+                                        Some(tx),
+                                        job_cancel.clone(),
+                                    ));
+                                    ResumeData::Job(Job {
+                                        receiver: Arc::new(Mutex::new(rx)),
+                                        cancel: job_cancel,
+                                    })
+                                }
+                                IO::Job { receiver: rx } => {
+                                    let r = rx.lock().await.recv().await;
+                                    match r {
+                                        Some(JobResult::Value(keys)) => ResumeData::Values(keys),
+                                        Some(JobResult::Error(err)) => ResumeData::Error(err),
+                                        // すでに wait され済みの job の場合
+                                        None => ResumeData::Nil,
+                                    }
+                                }
+                                IO::Async { name, args } => {
+                                    let handler = self.handlers.lock().unwrap().get(&name).cloned();
+                                    let handler = handler.unwrap_or_else(|| {
+                                        panic!("no async function registered: {}", name)
+                                    });
+                                    match handler(args).await {
+                                        Ok(d) => d,
+                                        Err(error) => ResumeData::Error(error.to_string()),
+                                    }
+                                }
                             }
-                            IO::Job { receiver: rx } => {
-                                let r = rx.lock().await.recv().await;
-                                if let Some(key) = r {
-                                    data = ResumeData::Key(key);
-                                } else {
-                                    // すでに wait され済みの job の場合
-                                    data = ResumeData::Nil;
+                        };
+                        tokio::pin!(io_fut);
+                        data = tokio::select! {
+                            _ = cancel.cancelled() => {
+                                if let Some(mut out) = out {
+                                    out.send(JobResult::Error("cancelled".to_string()))
+                                        .await
+                                        .unwrap();
                                 }
+                                break;
                             }
-                        }
+                            d = &mut io_fut => d,
+                        };
                     }
                 }
             }
         })
     }
     async fn resume_coroutine(&self, key: &RegistryKey, data: ResumeData) -> CoroutineStatus {
-        self.0.lock().await.context(|lua| {
+        self.lua.lock().await.context(|lua| {
             lua.expire_registry_values();
 
-            let data = match data {
-                ResumeData::Nil => Nil,
-                ResumeData::String(s) => s.to_lua(lua).unwrap(),
-                ResumeData::Key(key) => lua.registry_value(&key).unwrap(),
-                ResumeData::Job(job) => job.to_lua(lua).unwrap(),
-            };
             let coro: Thread = lua.registry_value(&key).unwrap();
 
             assert!(coro.status() == ThreadStatus::Resumable);
 
-            let ret: Value = coro.resume(data).unwrap();
+            // resume に渡す引数を全て Value のリストとして組み立てる。
+            // エラーを運ぶ場合は (nil, err_message) の形にし、
+            // `local value, err = coroutine.yield(...)` で受け取れるようにする。
+            let args: Vec<Value> = match data {
+                ResumeData::Nil => vec![Nil],
+                ResumeData::Values(keys) => keys
+                    .iter()
+                    .map(|key| lua.registry_value(key).unwrap())
+                    .collect(),
+                ResumeData::Job(job) => vec![job.to_lua(lua).unwrap()],
+                ResumeData::Error(err) => vec![Nil, err.to_lua(lua).unwrap()],
+                // 1 始まりの index の後ろに、ジョブが return した値を全て並べる。
+                // `local i, a, b = coroutine.yield(wait_any(...))` /
+                // `local i, _, err = coroutine.yield(wait_any(...))`
+                // のどちらでも受け取れるようにするため。
+                ResumeData::Selected { index, result } => {
+                    let mut args = vec![(index as i64 + 1).to_lua(lua).unwrap()];
+                    match result {
+                        JobResult::Value(keys) => {
+                            args.extend(keys.iter().map(|key| lua.registry_value(key).unwrap()));
+                        }
+                        JobResult::Error(err) => {
+                            args.push(Nil);
+                            args.push(err.to_lua(lua).unwrap());
+                        }
+                    }
+                    args
+                }
+            };
+
+            let args: Variadic<Value> = args.into_iter().collect();
+            let result: Result<rlua::MultiValue, rlua::Error> = coro.resume(args);
+
+            let ret = match result {
+                Ok(ret) => ret,
+                Err(error) => return CoroutineStatus::Errored { error },
+            };
+
             match coro.status() {
-                ThreadStatus::Resumable => match &ret {
-                    Value::UserData(u) => {
-                        if let Ok(io) = u.borrow::<IO>() {
-                            CoroutineStatus::Running {
-                                yielded: io.clone(),
+                // `coroutine.yield(...)` が運べる値は、host 側に何を待つかを
+                // 伝える `IO` 一つだけという決まりになっている（resume/finish
+                // 側の値は複数渡せるが、yield 側は単一の IO マーカーのみ）。
+                // 複数渡されてしまった場合、黙って残りを捨てるとバグに気付け
+                // ないので、ここでは確実に落とす。
+                ThreadStatus::Resumable => {
+                    assert!(
+                        ret.len() == 1,
+                        "coroutine.yield() must yield exactly one IO value, got {}: {:?}",
+                        ret.len(),
+                        ret
+                    );
+                    match ret.iter().next() {
+                        Some(Value::UserData(u)) => {
+                            if let Ok(io) = u.borrow::<IO>() {
+                                CoroutineStatus::Running {
+                                    yielded: io.clone(),
+                                }
+                            } else {
+                                panic!("unexpected value yielded: {:?}", ret)
                             }
-                        } else {
-                            panic!("unexpected value yielded: {:?}", ret)
                         }
+                        _ => panic!("unexpected value yielded: {:?}", ret),
                     }
-                    _ => panic!("unexpected value yielded: {:?}", ret),
-                },
+                }
                 ThreadStatus::Unresumable => {
-                    let key = lua.create_registry_value(ret).unwrap();
-                    CoroutineStatus::Finished { retvalue: key }
+                    let retvalues = ret
+                        .into_iter()
+                        .map(|v| lua.create_registry_value(v).unwrap())
+                        .collect();
+                    CoroutineStatus::Finished { retvalues }
                 }
-                ThreadStatus::Error => todo!("coroutine error case"),
+                // resume が Err を返した場合は上で抜けているので、ここに来るのは
+                // 念のためのフォールバックとして扱う。
+                ThreadStatus::Error => CoroutineStatus::Errored {
+                    error: rlua::Error::RuntimeError(
+                        "coroutine is in an errored state".to_string(),
+                    ),
+                },
             }
         })
     }
@@ -213,7 +697,128 @@ async fn main() {
     args.next().unwrap();
     let path = args.next().expect("script filename required");
     let path = Path::new(&path);
+
     let app = App::new();
     app.load(&path).await;
     app.main().await;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// スクリプトを文字列から読み込んで最後まで走らせ、完走後の `App` を返す。
+    /// スクリプト側は検証結果を `PASS` というグローバル変数に書き込む。
+    ///
+    /// `App::main()` はレジストリに積まれた関数値をコルーチンの本体として
+    /// 走らせる。スクリプト本体をそのまま `eval()` すると戻り値が無くて
+    /// `Function` への変換に失敗するので、`return function() ... end` で
+    /// 包んでから読み込む。
+    async fn run_script(script: &str) -> App {
+        let app = App::new();
+        let wrapped = format!("return function() {} end", script);
+        app.lua.lock().await.context(|lua| {
+            let main: Function = lua.load(&wrapped).set_name("test").unwrap().eval().unwrap();
+            lua.set_named_registry_value(App::MAIN, main).unwrap();
+        });
+        app.clone().main().await;
+        app
+    }
+
+    async fn global_bool(app: &App, name: &str) -> bool {
+        app.lua
+            .lock()
+            .await
+            .context(|lua| lua.globals().get(name).unwrap_or(false))
+    }
+
+    #[tokio::test]
+    async fn wait_any_does_not_clobber_lua_builtin_select() {
+        let app = run_script(
+            r#"
+                PASS = (select('#', 1, 2, 3) == 3)
+            "#,
+        )
+        .await;
+        assert!(global_bool(&app, "PASS").await);
+    }
+
+    #[tokio::test]
+    async fn wait_any_returns_index_of_first_finisher() {
+        let app = run_script(
+            r#"
+                local slow = coroutine.yield(forkio(function() coroutine.yield(sleep(1)) end))
+                local fast = coroutine.yield(forkio(function() end))
+                local i = coroutine.yield(wait_any(slow, fast))
+                PASS = (i == 2)
+            "#,
+        )
+        .await;
+        assert!(global_bool(&app, "PASS").await);
+    }
+
+    #[tokio::test]
+    async fn job_wait_returns_all_return_values() {
+        let app = run_script(
+            r#"
+                local job = coroutine.yield(forkio(function() return 1, 2 end))
+                local a, b = coroutine.yield(job:wait())
+                PASS = (a == 1 and b == 2)
+            "#,
+        )
+        .await;
+        assert!(global_bool(&app, "PASS").await);
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "coroutine.yield() must yield exactly one IO value")]
+    async fn yielding_more_than_one_value_panics_instead_of_dropping_it() {
+        run_script(
+            r#"
+                coroutine.yield(nop(), "extra")
+            "#,
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn coroutine_errors_carry_a_traceback() {
+        let app = run_script(
+            r#"
+                local job = coroutine.yield(forkio(function()
+                    error("boom")
+                end))
+                local _, err = coroutine.yield(job:wait())
+                PASS = (err ~= nil and err:find("stack traceback") ~= nil)
+            "#,
+        )
+        .await;
+        assert!(global_bool(&app, "PASS").await);
+    }
+
+    #[tokio::test]
+    async fn cancel_stops_a_job_blocked_in_sleep_without_waiting_it_out() {
+        // 子コルーチンが `sleep(3600)` で yield 中でも、cancel() が即座に
+        // 効くことを確認する。以前の実装だと、次の resume 境界である
+        // sleep の完了（＝ 3600 秒後）までキャンセルに気付けなかった。
+        let result = tokio::time::timeout(
+            Duration::from_millis(500),
+            run_script(
+                r#"
+                    local job = coroutine.yield(forkio(function() coroutine.yield(sleep(3600)) end))
+                    -- 子コルーチンに sleep(3600) まで進ませてからキャンセルする。
+                    coroutine.yield(nop())
+                    job:cancel()
+                    local _, err = coroutine.yield(job:wait())
+                    PASS = (err == "cancelled")
+                "#,
+            ),
+        )
+        .await;
+        assert!(
+            result.is_ok(),
+            "job:wait() after cancel() should not block on the job's in-flight sleep()"
+        );
+        assert!(global_bool(&result.unwrap(), "PASS").await);
+    }
+}